@@ -7,9 +7,20 @@ fn main() {
     assert!(ipt.append("nat", "TESTINGCHAIN", "-j ACCEPT").is_ok());
     assert!(ipt.exists("nat", "TESTINGCHAIN", "-j ACCEPT").unwrap());
     assert!(ipt.delete("nat", "TESTINGCHAIN", "-j ACCEPT").is_ok());
-    assert!(ipt.save_all("test").is_ok());
-    assert!(ipt.restore_all("test").is_ok());
+    assert!(ipt.save_all_to_file("test").is_ok());
+    assert!(ipt.restore_all_from_file("test").is_ok());
     assert!(ipt.delete_chain("nat", "TESTINGCHAIN").is_ok());
 
     assert!(ipt.change_policy("filter", "FORWARD", "ACCEPT").is_ok());
+
+    assert!(iptables::Transaction::new()
+        .noflush(true)
+        .new_chain("nat", "TESTINGTXNCHAIN")
+        .append("nat", "TESTINGTXNCHAIN", "-j ACCEPT")
+        .commit(&ipt)
+        .is_ok());
+    assert!(ipt
+        .exists("nat", "TESTINGTXNCHAIN", "-j ACCEPT")
+        .unwrap());
+    assert!(ipt.flush_and_delete_chain("nat", "TESTINGTXNCHAIN").is_ok());
 }
@@ -5,9 +5,11 @@ use std::convert::From;
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::Write;
 use std::os::unix::io::AsRawFd;
-use std::process::{Command, Output};
+use std::process::{Command, Output, Stdio};
+use std::thread;
 use std::vec::Vec;
 
 lazy_static! {
@@ -32,6 +34,36 @@ fn error_from_str(msg: &str) -> Box<dyn Error> {
     msg.into()
 }
 
+/// Re-joins the tokens from `split_quoted` into a single `iptables-restore`
+/// line, re-quoting any token that contains whitespace so it still parses
+/// as one argument (plain `join(" ")` would let it split back apart).
+fn quote_for_restore(rule: &str) -> String {
+    rule.split_quoted()
+        .into_iter()
+        .map(|token| {
+            if token.contains(char::is_whitespace) {
+                format!("\"{}\"", token)
+            } else {
+                token.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Kernel-provided chains for the `filter` table. These always exist and
+/// cannot be deleted or renamed.
+pub const BUILTIN_CHAINS_FILTER: &[&str] = &["INPUT", "FORWARD", "OUTPUT"];
+/// Kernel-provided chains for the `mangle` table.
+pub const BUILTIN_CHAINS_MANGLE: &[&str] =
+    &["PREROUTING", "OUTPUT", "INPUT", "FORWARD", "POSTROUTING"];
+/// Kernel-provided chains for the `nat` table.
+pub const BUILTIN_CHAINS_NAT: &[&str] = &["PREROUTING", "POSTROUTING", "OUTPUT"];
+/// Kernel-provided chains for the `raw` table.
+pub const BUILTIN_CHAINS_RAW: &[&str] = &["PREROUTING", "OUTPUT"];
+/// Kernel-provided chains for the `security` table.
+pub const BUILTIN_CHAINS_SECURITY: &[&str] = &["INPUT", "OUTPUT", "FORWARD"];
+
 fn output_to_result(output: Output) -> Result<(), Box<dyn Error>> {
     if !output.status.success() {
         return Err(Box::new(IptablesError::from(output)));
@@ -72,8 +104,44 @@ pub struct IPTables {
     pub v_major: isize,
     pub v_minor: isize,
     pub v_patch: isize,
+
+    /// Timeout in seconds passed to `--wait` on iptables versions that
+    /// support it. `None` waits indefinitely (the historical behavior).
+    pub wait_seconds: Option<u32>,
+    /// Lock file used to serialize access on iptables versions that lack
+    /// `--wait`. Defaults to `/var/run/xtables_old.lock`.
+    pub lock_file: String,
+    /// Number of non-blocking `flock` retries on iptables versions that
+    /// lack `--wait`, before giving up.
+    pub lock_retries: u32,
 }
 
+impl IPTables {
+    /// Sets the `--wait` timeout, in seconds, used on iptables versions
+    /// that support it.
+    pub fn with_wait_seconds(mut self, seconds: u32) -> Self {
+        self.wait_seconds = Some(seconds);
+        self
+    }
+
+    /// Sets the fallback lock file path used on iptables versions that
+    /// lack `--wait`.
+    pub fn with_lock_file(mut self, path: &str) -> Self {
+        self.lock_file = path.to_string();
+        self
+    }
+
+    /// Sets the number of non-blocking `flock` retries used on iptables
+    /// versions that lack `--wait`.
+    pub fn with_lock_retries(mut self, retries: u32) -> Self {
+        self.lock_retries = retries;
+        self
+    }
+}
+
+/// The documented entry point for constructing an [`IPTables`] handle.
+/// Pass `true` to drive `ip6tables` instead of `iptables`; detects the
+/// binary set and version for the chosen protocol.
 #[cfg(target_os = "linux")]
 pub fn new_with_protocol(is_ipv6: bool) -> Result<IPTables, Box<dyn Error>> {
     let cmd = if is_ipv6 { "ip6tables" } else { "iptables" };
@@ -123,6 +191,9 @@ pub fn new_with_protocol(is_ipv6: bool) -> Result<IPTables, Box<dyn Error>> {
         v_major: v_major as isize,
         v_minor: v_minor as isize,
         v_patch: v_patch as isize,
+        wait_seconds: None,
+        lock_file: "/var/run/xtables_old.lock".to_string(),
+        lock_retries: 10,
     })
 }
 
@@ -137,28 +208,98 @@ pub fn new() -> Result<IPTables, Box<dyn Error>> {
 }
 
 impl IPTables {
-    pub fn save_table(&self, table: &str, target: &str) -> Result<Output, Box<dyn Error>> {
-        let cmd = format!("{} -t {} > {}", self.save_cmd, table, target);
-        let output = Command::new("sh").arg("-c").arg(cmd).output()?;
-        Ok(output)
+    /// Dumps the ruleset for `table` via `iptables-save -t <table>` and
+    /// returns it as a string, without touching the filesystem.
+    pub fn save_table(&self, table: &str) -> Result<String, Box<dyn Error>> {
+        let output = Command::new(self.save_cmd).args(["-t", table]).output()?;
+        if !output.status.success() {
+            return Err(Box::new(IptablesError::from(output)));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
 
-    pub fn save_all(&self, target: &str) -> Result<Output, Box<dyn Error>> {
-        let cmd = format!("{} > {}", self.save_cmd, target);
-        let output = Command::new("sh").arg("-c").arg(cmd).output()?;
-        Ok(output)
+    /// Dumps the ruleset for every table via `iptables-save` and returns it
+    /// as a string, without touching the filesystem.
+    pub fn save_all(&self) -> Result<String, Box<dyn Error>> {
+        let output = Command::new(self.save_cmd).output()?;
+        if !output.status.success() {
+            return Err(Box::new(IptablesError::from(output)));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
 
-    pub fn restore_table(&self, table: &str, target: &str) -> Result<Output, Box<dyn Error>> {
-        let cmd = format!("{} -t {} < {}", self.restore_cmd, table, target);
-        let output = Command::new("sh").arg("-c").arg(cmd).output()?;
-        Ok(output)
+    /// Loads `data` into `table` via `iptables-restore -t <table>`, feeding
+    /// it to the child process's stdin directly.
+    pub fn restore_table(&self, table: &str, data: &str) -> Result<(), Box<dyn Error>> {
+        self.run_restore(&["-t", table], data)
     }
 
-    pub fn restore_all(&self, target: &str) -> Result<Output, Box<dyn Error>> {
-        let cmd = format!("{} < {}", self.restore_cmd, target);
-        let output = Command::new("sh").arg("-c").arg(cmd).output()?;
-        Ok(output)
+    /// Loads `data` for all tables via `iptables-restore`, feeding it to the
+    /// child process's stdin directly.
+    pub fn restore_all(&self, data: &str) -> Result<(), Box<dyn Error>> {
+        self.run_restore(&[], data)
+    }
+
+    fn run_restore(&self, args: &[&str], data: &str) -> Result<(), Box<dyn Error>> {
+        let mut child = Command::new(self.restore_cmd)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| error_from_str("failed to open restore child stdin"))?;
+        let data = data.to_string();
+
+        // Feed stdin from a separate thread: iptables-restore may write
+        // enough stdout/stderr to fill its pipe before it has drained a
+        // large ruleset from stdin, which would deadlock a blocking
+        // write_all here against wait_with_output below.
+        let writer = thread::spawn(move || stdin.write_all(data.as_bytes()));
+
+        let output = child.wait_with_output()?;
+
+        // Check the command's own result first: if iptables-restore exited
+        // early on a malformed ruleset, the writer thread's write_all can
+        // fail with a broken pipe that would otherwise mask the real error
+        // sitting in output.stderr.
+        output_to_result(output)?;
+        writer
+            .join()
+            .map_err(|_| error_from_str("restore stdin writer thread panicked"))??;
+
+        Ok(())
+    }
+
+    /// Thin wrapper around `save_table` that writes the dump to `target`.
+    pub fn save_table_to_file(&self, table: &str, target: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(target, self.save_table(table)?)?;
+        Ok(())
+    }
+
+    /// Thin wrapper around `save_all` that writes the dump to `target`.
+    pub fn save_all_to_file(&self, target: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(target, self.save_all()?)?;
+        Ok(())
+    }
+
+    /// Thin wrapper around `restore_table` that reads the ruleset from
+    /// `target`.
+    pub fn restore_table_from_file(
+        &self,
+        table: &str,
+        target: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        self.restore_table(table, &fs::read_to_string(target)?)
+    }
+
+    /// Thin wrapper around `restore_all` that reads the ruleset from
+    /// `target`.
+    pub fn restore_all_from_file(&self, target: &str) -> Result<(), Box<dyn Error>> {
+        self.restore_all(&fs::read_to_string(target)?)
     }
 
     fn run<S: AsRef<OsStr>>(&self, args: &[S]) -> Result<Output, Box<dyn Error>> {
@@ -168,12 +309,16 @@ impl IPTables {
         let output;
 
         if self.has_wait {
-            output = output_cmd.args(args).arg("--wait").output()?;
+            output_cmd.args(args).arg("--wait");
+            if let Some(seconds) = self.wait_seconds {
+                output_cmd.arg(seconds.to_string());
+            }
+            output = output_cmd.output()?;
         } else {
-            file_lock = Some(File::create("/var/run/xtables_old.lock")?);
+            file_lock = Some(File::create(&self.lock_file)?);
 
             let mut need_retry = true;
-            let mut limit = 10;
+            let mut limit = self.lock_retries;
             while need_retry {
                 match flock(
                     file_lock.as_ref().unwrap().as_raw_fd(),
@@ -312,6 +457,31 @@ impl IPTables {
         Ok(list)
     }
 
+    /// Returns `true` if `chain` is one of the kernel's built-in chains for
+    /// `table`. Built-in chains always exist, cannot be created or deleted,
+    /// and are not affected by `-X`.
+    pub fn is_builtin_chain(&self, table: &str, chain: &str) -> bool {
+        let builtins: &[&str] = match table {
+            "filter" => BUILTIN_CHAINS_FILTER,
+            "mangle" => BUILTIN_CHAINS_MANGLE,
+            "nat" => BUILTIN_CHAINS_NAT,
+            "raw" => BUILTIN_CHAINS_RAW,
+            "security" => BUILTIN_CHAINS_SECURITY,
+            _ => &[],
+        };
+        builtins.contains(&chain)
+    }
+
+    /// Like `list_chains`, but filters out the table's built-in chains so
+    /// only user-created chains remain.
+    pub fn list_user_chains(&self, table: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        Ok(self
+            .list_chains(table)?
+            .into_iter()
+            .filter(|chain| !self.is_builtin_chain(table, chain))
+            .collect())
+    }
+
     pub fn chain_exists(&self, table: &str, chain: &str) -> Result<bool, Box<dyn Error>> {
         self.run(&["-t", table, "-L", chain])
             .map(|output| output.status.success())
@@ -338,11 +508,25 @@ impl IPTables {
     }
 
     pub fn delete_chain(&self, table: &str, chain: &str) -> Result<(), Box<dyn Error>> {
+        if self.is_builtin_chain(table, chain) {
+            return Err(error_from_str(&format!(
+                "cannot delete built-in chain {} in table {}",
+                chain, table
+            )));
+        }
+
         self.run(&["-t", table, "-X", chain])
             .and_then(output_to_result)
     }
 
     pub fn flush_and_delete_chain(&self, table: &str, chain: &str) -> Result<(), Box<dyn Error>> {
+        if self.is_builtin_chain(table, chain) {
+            return Err(error_from_str(&format!(
+                "cannot delete built-in chain {} in table {}",
+                chain, table
+            )));
+        }
+
         while self.chain_exists(table, chain)? {
             match self.flush_chain(table, chain) {
                 Ok(_) => {
@@ -387,3 +571,159 @@ impl IPTables {
         (self.v_major, self.v_minor, self.v_patch)
     }
 }
+
+struct TableOps {
+    name: String,
+    // Chains declared for this table, in declaration order, paired with
+    // their restore-time policy ("-" for non built-in chains).
+    chains: Vec<(String, String)>,
+    // Formatted `-A`/`-I`/`-D` rule lines, in the order they should be
+    // applied.
+    rules: Vec<String>,
+}
+
+impl TableOps {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            chains: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    fn declare_chain(&mut self, chain: &str, policy: Option<&str>) {
+        if let Some(entry) = self.chains.iter_mut().find(|(c, _)| c == chain) {
+            if let Some(policy) = policy {
+                entry.1 = policy.to_string();
+            }
+        } else {
+            self.chains
+                .push((chain.to_string(), policy.unwrap_or("-").to_string()));
+        }
+    }
+}
+
+/// Accumulates chain and rule operations across one or more tables and
+/// applies them in a single `iptables-restore` pass. Each table commits
+/// atomically: either all of that table's operations land or none do.
+/// Tables are committed one after another, so an earlier table's changes
+/// stay applied if a later table fails.
+///
+/// By default `commit()` flushes every table it touches before applying
+/// the batch — this is `iptables-restore`'s own default behavior, not
+/// something this type adds. Any existing rule in a table that isn't
+/// re-added by this `Transaction` is lost. Call `.noflush(true)` to
+/// augment a table's existing rules instead of replacing them.
+///
+/// ```no_run
+/// # use rust_iptables::iptables;
+/// let ipt = iptables::new().unwrap();
+/// iptables::Transaction::new()
+///     .noflush(true)
+///     .new_chain("filter", "LOGGING")
+///     .append("filter", "LOGGING", "-j LOG")
+///     .append("filter", "INPUT", "-j LOGGING")
+///     .commit(&ipt)
+///     .unwrap();
+/// ```
+pub struct Transaction {
+    noflush: bool,
+    tables: Vec<TableOps>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self {
+            noflush: false,
+            tables: Vec::new(),
+        }
+    }
+
+    /// When set, the batch is restored with `--noflush` so it augments the
+    /// table's existing rules instead of replacing them.
+    pub fn noflush(mut self, noflush: bool) -> Self {
+        self.noflush = noflush;
+        self
+    }
+
+    fn table(&mut self, table: &str) -> &mut TableOps {
+        if !self.tables.iter().any(|t| t.name == table) {
+            self.tables.push(TableOps::new(table));
+        }
+        self.tables.iter_mut().find(|t| t.name == table).unwrap()
+    }
+
+    /// Queues creation of a user chain.
+    pub fn new_chain(mut self, table: &str, chain: &str) -> Self {
+        self.table(table).declare_chain(chain, None);
+        self
+    }
+
+    /// Queues setting `chain`'s policy.
+    pub fn policy(mut self, table: &str, chain: &str, target: &str) -> Self {
+        self.table(table).declare_chain(chain, Some(target));
+        self
+    }
+
+    /// Queues appending `rule` to `chain`. `chain` is not declared in the
+    /// restore header unless `.new_chain()`/`.policy()` is also called for
+    /// it — `-A` doesn't require that for a chain that already exists
+    /// (e.g. a built-in one).
+    pub fn append(mut self, table: &str, chain: &str, rule: &str) -> Self {
+        let rule = quote_for_restore(rule);
+        self.table(table)
+            .rules
+            .push(format!("-A {} {}", chain, rule));
+        self
+    }
+
+    /// Queues inserting `rule` into `chain` at `position`. See `append` for
+    /// why `chain` isn't implicitly declared.
+    pub fn insert(mut self, table: &str, chain: &str, position: i32, rule: &str) -> Self {
+        let rule = quote_for_restore(rule);
+        self.table(table)
+            .rules
+            .push(format!("-I {} {} {}", chain, position, rule));
+        self
+    }
+
+    /// Queues deleting `rule` from `chain`. See `append` for why `chain`
+    /// isn't implicitly declared.
+    pub fn delete(mut self, table: &str, chain: &str, rule: &str) -> Self {
+        let rule = quote_for_restore(rule);
+        self.table(table)
+            .rules
+            .push(format!("-D {} {}", chain, rule));
+        self
+    }
+
+    fn to_restore_format(&self) -> String {
+        let mut buf = String::new();
+        for table in &self.tables {
+            buf.push_str(&format!("*{}\n", table.name));
+            for (chain, policy) in &table.chains {
+                buf.push_str(&format!(":{} {} [0:0]\n", chain, policy));
+            }
+            for rule in &table.rules {
+                buf.push_str(rule);
+                buf.push('\n');
+            }
+            buf.push_str("COMMIT\n");
+        }
+        buf
+    }
+
+    /// Serializes the queued operations into `iptables-restore` syntax and
+    /// applies them in a single pass. Each table commits atomically: either
+    /// all of its operations land or none do.
+    pub fn commit(&self, ipt: &IPTables) -> Result<(), Box<dyn Error>> {
+        let args: &[&str] = if self.noflush { &["--noflush"] } else { &[] };
+        ipt.run_restore(args, &self.to_restore_format())
+    }
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}